@@ -0,0 +1,105 @@
+use std::path::Path;
+
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+
+/// Label key applied to host-operator-owned resources (CRDs, webhooks, ...).
+pub static DEFAULT_HOST_LABEL_KEY: &str =
+    "operators.coreos.com/toolchain-host-operator.toolchain-host-operator";
+/// Label key applied to member-operator-owned resources.
+pub static DEFAULT_MEMBER_LABEL_KEY: &str =
+    "operators.coreos.com/toolchain-member-operator.toolchain-member-operator";
+/// Finalizer kubesaw places on custom resources to gate their deletion.
+pub static DEFAULT_FINALIZER: &str = "finalizer.toolchain.dev.openshift.com";
+
+/// User-overridable label/finalizer selectors.
+///
+/// Kubesaw renames operator packages and finalizers across releases (and
+/// forks may use different ones entirely), so these are kept out of compiled
+/// constants and loaded from an optional `--config` file instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Label selector identifying host-operator-owned resources.
+    pub host_label: String,
+    /// Label selector identifying member-operator-owned resources.
+    pub member_label: String,
+    /// Finalizers to strip from custom resources before deleting their CRDs.
+    pub finalizers: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host_label: DEFAULT_HOST_LABEL_KEY.to_owned(),
+            member_label: DEFAULT_MEMBER_LABEL_KEY.to_owned(),
+            finalizers: vec![DEFAULT_FINALIZER.to_owned()],
+        }
+    }
+}
+
+impl Config {
+    /// Loads a [`Config`] from `path`, falling back to [`Config::default`] when no path is given.
+    pub fn load(path: Option<&Path>) -> color_eyre::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read config file {}", path.display()))?;
+        serde_yaml::from_str(&contents)
+            .wrap_err_with(|| format!("failed to parse config file {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_temp_config(contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "kubesaw-cleaner-test-config-{}-{:?}.yaml",
+            std::process::id(),
+            std::thread::current().id(),
+        ));
+        std::fs::write(&path, contents).expect("failed to write temp config file");
+        path
+    }
+
+    #[test]
+    fn default_has_expected_values() {
+        let config = Config::default();
+        assert_eq!(config.host_label, DEFAULT_HOST_LABEL_KEY);
+        assert_eq!(config.member_label, DEFAULT_MEMBER_LABEL_KEY);
+        assert_eq!(config.finalizers, vec![DEFAULT_FINALIZER.to_owned()]);
+    }
+
+    #[test]
+    fn load_without_path_falls_back_to_default() {
+        let config = Config::load(None).unwrap();
+        assert_eq!(config.host_label, DEFAULT_HOST_LABEL_KEY);
+        assert_eq!(config.member_label, DEFAULT_MEMBER_LABEL_KEY);
+        assert_eq!(config.finalizers, vec![DEFAULT_FINALIZER.to_owned()]);
+    }
+
+    #[test]
+    fn load_overriding_only_finalizers_keeps_default_labels() {
+        let path = write_temp_config("finalizers:\n  - custom.finalizer/foo\n");
+        let result = Config::load(Some(&path));
+        std::fs::remove_file(&path).ok();
+        let config = result.unwrap();
+
+        assert_eq!(config.host_label, DEFAULT_HOST_LABEL_KEY);
+        assert_eq!(config.member_label, DEFAULT_MEMBER_LABEL_KEY);
+        assert_eq!(config.finalizers, vec!["custom.finalizer/foo".to_owned()]);
+    }
+
+    #[test]
+    fn load_rejects_malformed_file() {
+        let path = write_temp_config("finalizers: [this is not valid yaml for this struct\n");
+        let result = Config::load(Some(&path));
+        std::fs::remove_file(&path).ok();
+
+        assert!(result.is_err());
+    }
+}
@@ -1,36 +1,58 @@
 use color_eyre::eyre::{eyre, Context};
+use futures::stream::{self, StreamExt};
+use json_patch::{Patch as JsonPatch, PatchOperation, ReplaceOperation, TestOperation};
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    api::{ApiResource, DynamicObject, GroupVersionKind, ListParams, ObjectList},
+    api::{DeleteParams, DynamicObject, ListParams, Patch, PatchParams},
     core::Expression,
+    discovery::{ApiCapabilities, Discovery, Scope},
     Api, Client, Resource, ResourceExt,
 };
-use tracing::{error, info, instrument, trace};
+use serde_json::json;
+use tracing::{error, info, instrument, trace, warn};
 
+/// How many attempts (the first try plus retries after conflicting writes)
+/// to make at stripping a finalizer before giving up on that custom resource.
+const MAX_PATCH_ATTEMPTS: u32 = 5;
+
+/// Base delay for the linear backoff between retried finalizer patches;
+/// attempt `n` waits `n * PATCH_RETRY_BACKOFF` before trying again.
+const PATCH_RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Patches every instance of `crd` and then deletes the CRD itself, returning
+/// the identifiers (`namespace/name`, or bare `name` for cluster-scoped CRs)
+/// of every custom resource whose finalizer was actually stripped (or, under
+/// `--dry-run`, would have been) — used by the caller to build up a plan of
+/// everything this run touched.
 pub async fn process_crd(
     client: &Client,
+    discovery: &Discovery,
     crd: &CustomResourceDefinition,
-) -> color_eyre::Result<()> {
+    concurrency: usize,
+    dry_run: bool,
+    finalizers: &[String],
+) -> color_eyre::Result<Vec<String>> {
     info!(name = crd.name_any(), "patching crd instances");
-    let _ = patch_crs(&client, &crd).await.inspect_err(|e| {
-        error!(
-            "Failed to patch custom resource of type {:?}",
-            crd.name_any()
-        );
-        eprint!("{e}")
-    });
-
-    remove_crd(&client, crd)
+    let stripped_crs = patch_crs(client, discovery, crd, concurrency, dry_run, finalizers)
+        .await
+        .wrap_err_with(|| {
+            format!(
+                "failed to patch custom resources of type {:?}, leaving crd in place",
+                crd.name_any()
+            )
+        })?;
+
+    remove_crd(&client, crd, dry_run)
         .await
         .wrap_err_with(|| format!("failed to delete crd {}", crd.name_any()))?;
-    Ok(())
+    Ok(stripped_crs)
 }
 
 /// fetches all [CustomResourceDefinition] instances with a given label.
 pub async fn fetch_crds(
     client: &Client,
     label: &str,
-) -> color_eyre::Result<ObjectList<CustomResourceDefinition>> {
+) -> color_eyre::Result<kube::api::ObjectList<CustomResourceDefinition>> {
     let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
 
     let host_expr = Expression::Exists(label.to_owned());
@@ -42,83 +64,237 @@ pub async fn fetch_crds(
 
 /// deletes a crd
 #[instrument(skip_all, fields(crd_name = crd.name_any()))]
-pub async fn remove_crd(client: &Client, crd: &CustomResourceDefinition) -> color_eyre::Result<()> {
+pub async fn remove_crd(
+    client: &Client,
+    crd: &CustomResourceDefinition,
+    dry_run: bool,
+) -> color_eyre::Result<()> {
     let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    let dp = DeleteParams {
+        dry_run,
+        ..Default::default()
+    };
     if let Some(name) = crd.metadata.name.as_deref() {
-        crds.delete(name, &Default::default())
+        crds.delete(name, &dp)
             .await?
-            .map_left(|_| info!("deleting crd"))
-            .map_right(|_| info!("deleted crd"));
+            .map_left(|_| info!(dry_run, "deleting crd"))
+            .map_right(|_| info!(dry_run, "deleted crd"));
     }
     Ok(())
 }
 
-/// For a given custom resource definition, remove the finalizer for all instances
+/// Finds the best served version of `crd` in an already-populated `discovery`
+/// snapshot, along with the resource's scope and capabilities.
+///
+/// Rather than trusting the CRD's storage version (which is not required to
+/// be served), this walks the served versions in the apiserver's preferred
+/// order and returns the first one discovery actually knows about. Discovery
+/// is run once by the caller and shared across every CRD rather than
+/// re-scanned here, since a full discovery run is a single expensive
+/// cluster-wide call.
+fn discover_cr_resource(
+    discovery: &Discovery,
+    crd: &CustomResourceDefinition,
+) -> color_eyre::Result<(kube::api::ApiResource, ApiCapabilities)> {
+    let group = discovery.groups().find(|group| group.name() == crd.spec.group).ok_or_else(|| {
+        error!(name = crd.meta().name, group = crd.spec.group, "failed to discover crd group");
+        eyre!("no discovery information for group {}", crd.spec.group)
+    })?;
+
+    for version in group.versions() {
+        if let Some((ar, caps)) = group
+            .versioned_resources(version)
+            .into_iter()
+            .find(|(ar, _)| ar.kind == crd.spec.names.kind)
+        {
+            return Ok((ar, caps));
+        }
+    }
+
+    error!(name = crd.meta().name, "failed to discover a served version of crd");
+    Err(eyre!("CRD discovery failed"))
+}
+
+/// For a given custom resource definition, remove the finalizer for all
+/// instances, returning the identifier of every instance actually (or, under
+/// `--dry-run`, notionally) stripped.
 #[instrument(skip_all, fields(crd_name = crd.name_any()))]
-pub async fn patch_crs(client: &Client, crd: &CustomResourceDefinition) -> color_eyre::Result<()> {
-    let version = crd
-        .spec
-        .versions
-        .iter()
-        .find(|v| v.storage)
-        .map(|v| v.name.clone())
-        .ok_or_else(|| {
-            error!(
-                name = crd.meta().name,
-                "failed to find storage version of crd"
-            );
-            color_eyre::eyre::eyre!("CRD parsing failed")
-        })?;
-    let dyntype = ApiResource::from_gvk_with_plural(
-        &GroupVersionKind::gvk(&crd.spec.group, &version, &crd.spec.names.kind),
-        &crd.spec.names.plural,
-    );
+pub async fn patch_crs(
+    client: &Client,
+    discovery: &Discovery,
+    crd: &CustomResourceDefinition,
+    concurrency: usize,
+    dry_run: bool,
+    finalizers: &[String],
+) -> color_eyre::Result<Vec<String>> {
+    let (dyntype, caps) = discover_cr_resource(discovery, crd)?;
     trace!(
         version = dyntype.version,
         group = dyntype.group,
         kind = dyntype.kind,
         apiVersion = dyntype.api_version,
-        plural = dyntype.plural
+        plural = dyntype.plural,
+        scope = ?caps.scope,
     );
-    let cr_api: Api<DynamicObject> = Api::all_with(client.clone(), &dyntype);
 
-    for (namespace, name) in cr_api
-        .list(&ListParams::default())
-        .await
-        .map_err(|err| {
-            error!(?err, "failed to retrieve custom resources");
-            eyre!("failed to retrieve custom resources: {:?}", err)
-        })?
-        .iter()
-        .filter_map(|cr| {
-            Some((
-                cr.metadata.namespace.as_deref()?,
-                cr.metadata.name.as_deref()?,
-            ))
+    let list_api: Api<DynamicObject> = Api::all_with(client.clone(), &dyntype);
+    let crs = list_api.list(&ListParams::default()).await.map_err(|err| {
+        error!(?err, "failed to retrieve custom resources");
+        eyre!("failed to retrieve custom resources: {:?}", err)
+    })?;
+
+    let results: Vec<_> = stream::iter(crs.into_iter())
+        .map(|cr| {
+            let dyntype = dyntype.clone();
+            let scope = caps.scope.clone();
+            async move {
+                let Some(name) = cr.metadata.name.clone() else {
+                    return Ok(None);
+                };
+                let namespace = cr.metadata.namespace.clone();
+                let identifier = match namespace.as_deref() {
+                    Some(namespace) => format!("{namespace}/{name}"),
+                    None => name.clone(),
+                };
+
+                let api: Api<DynamicObject> = match scope {
+                    Scope::Cluster => Api::all_with(client.clone(), &dyntype),
+                    Scope::Namespaced => {
+                        let Some(namespace) = namespace.as_deref() else {
+                            error!(name, "namespaced custom resource is missing a namespace");
+                            return Err(eyre!(
+                                "namespaced custom resource {name} is missing a namespace"
+                            ));
+                        };
+                        Api::namespaced_with(client.clone(), namespace, &dyntype)
+                    }
+                };
+
+                strip_finalizer(&api, &name, namespace.as_deref(), dry_run, finalizers)
+                    .await
+                    .inspect_err(|err| {
+                        error!(?err, ?namespace, name, "failed to update finalizers");
+                    })
+                    .map(|stripped| stripped.then_some(identifier))
+            }
         })
-    {
-        let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), namespace, &dyntype);
-        let mut object = api.get(name).await.map_err(|err| {
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut stripped = Vec::new();
+    let mut failed = 0;
+    for result in &results {
+        match result {
+            Ok(Some(identifier)) => stripped.push(identifier.clone()),
+            Ok(None) => {}
+            Err(_) => failed += 1,
+        }
+    }
+
+    info!(
+        succeeded = results.len() - failed,
+        stripped = stripped.len(),
+        failed,
+        "finished patching custom resources"
+    );
+    check_patch_failures(failed)?;
+
+    Ok(stripped)
+}
+
+/// Turns a count of failed finalizer patches into an error, so that a CRD
+/// whose CRs couldn't all be patched is left in place instead of deleted
+/// out from under the ones still holding the finalizer.
+///
+/// Split out from `patch_crs` as a pure function so the "any failure leaves
+/// the CRD in place" policy is unit-testable without a live apiserver.
+fn check_patch_failures(failed: usize) -> color_eyre::Result<()> {
+    if failed > 0 {
+        return Err(eyre!(
+            "failed to strip finalizers from {failed} custom resource(s), leaving crd in place"
+        ));
+    }
+    Ok(())
+}
+
+/// Removes every finalizer in `finalizers` from a custom resource's
+/// `metadata.finalizers` via a JSON Patch, retrying with a fresh `test` op
+/// and a linear backoff whenever the apiserver reports a 409 Conflict (i.e.
+/// the object changed between our `get` and `patch`). Returns whether a
+/// finalizer was actually removed, so a CR that never had one isn't counted
+/// as touched.
+async fn strip_finalizer(
+    api: &Api<DynamicObject>,
+    name: &str,
+    namespace: Option<&str>,
+    dry_run: bool,
+    finalizers: &[String],
+) -> color_eyre::Result<bool> {
+    let pp = PatchParams {
+        dry_run,
+        ..Default::default()
+    };
+
+    for attempt in 1..=MAX_PATCH_ATTEMPTS {
+        let object = api.get(name).await.map_err(|err| {
             error!(?err, namespace, name, "failed to retrieve object");
             eyre!("failed to retrieve object: {:?}", err)
         })?;
-        if let Some(finalizers) = object.metadata.finalizers {
-            let new_finalizers = finalizers
-                .iter()
-                .filter(|f| *f != "finalizer.toolchain.dev.openshift.com")
-                .cloned()
-                .collect();
-
-            object.metadata.finalizers = Some(new_finalizers);
-            info!(name, namespace, "patching custom resource");
-            let _ = api
-                .replace(name, &Default::default(), &object)
-                .await
-                .inspect_err(|err| {
-                    error!(?err, namespace, name, "failed to update finalizers");
-                });
+
+        let Some(current_finalizers) = object.metadata.finalizers else {
+            return Ok(false);
+        };
+        if !current_finalizers.iter().any(|f| finalizers.contains(f)) {
+            return Ok(false);
+        }
+        let new_finalizers: Vec<_> = current_finalizers
+            .iter()
+            .filter(|f| !finalizers.contains(f))
+            .cloned()
+            .collect();
+
+        let patch = JsonPatch(vec![
+            PatchOperation::Test(TestOperation {
+                path: "/metadata/finalizers".parse().unwrap(),
+                value: json!(current_finalizers),
+            }),
+            PatchOperation::Replace(ReplaceOperation {
+                path: "/metadata/finalizers".parse().unwrap(),
+                value: json!(new_finalizers),
+            }),
+        ]);
+
+        info!(name, namespace, attempt, dry_run, "patching custom resource");
+        match api.patch(name, &pp, &Patch::Json::<()>(patch)).await {
+            Ok(_) => return Ok(true),
+            Err(kube::Error::Api(err)) if err.code == 409 => {
+                let backoff = PATCH_RETRY_BACKOFF * attempt;
+                warn!(name, namespace, attempt, ?backoff, "conflict stripping finalizer, retrying");
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+            Err(err) => return Err(eyre!("failed to patch finalizers: {:?}", err)),
         }
     }
 
-    Ok(())
+    Err(eyre!(
+        "failed to strip finalizer from {name} after {MAX_PATCH_ATTEMPTS} attempts due to repeated conflicts"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_patch_failures_ok_when_none_failed() {
+        assert!(check_patch_failures(0).is_ok());
+    }
+
+    #[test]
+    fn check_patch_failures_errs_when_any_failed() {
+        let err = check_patch_failures(3).unwrap_err();
+        assert!(err.to_string().contains('3'));
+    }
 }
@@ -2,15 +2,24 @@ use std::path::PathBuf;
 
 use clap::{Args, Parser, Subcommand};
 use color_eyre::eyre::Context;
+use futures::stream::{self, StreamExt};
 use itertools::Itertools;
-use k8s_openapi::api::admissionregistration::v1::ValidatingWebhookConfiguration;
-use kube::{Api, Client};
-use tracing::{info, instrument, level_filters::LevelFilter};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::{discovery::Discovery, Client, ResourceExt};
+use tracing::{error, info, instrument, level_filters::LevelFilter};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+use crate::config::Config;
 use crate::crds::{fetch_crds, process_crd};
+use crate::webhooks::remove_webhook_configs;
 
+mod config;
 mod crds;
+mod webhooks;
+
+/// Default number of CRDs (and, within a CRD, custom resource instances)
+/// processed concurrently.
+const DEFAULT_CONCURRENCY: usize = 10;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -18,15 +27,27 @@ struct App {
     #[arg(short, long)]
     kubeconfig: Option<PathBuf>,
 
+    /// How many CRDs (and custom resource instances within a CRD) to process at once.
+    #[arg(long, default_value_t = DEFAULT_CONCURRENCY)]
+    concurrency: usize,
+
+    /// Preview changes without mutating the cluster: every write is sent with
+    /// a server-side dry run so the apiserver validates it but nothing is
+    /// persisted. A plan summary of the webhook resources touched is logged
+    /// once webhook removal finishes, and one of every CRD and custom
+    /// resource touched once CRD processing finishes.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Path to a config file overriding the host/member label selectors and
+    /// the finalizers to strip (see [`Config`]).
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
-static HOST_LABEL_KEY: &str =
-    "operators.coreos.com/toolchain-host-operator.toolchain-host-operator";
-static MEMBER_LABEL_KEY: &str =
-    "operators.coreos.com/toolchain-member-operator.toolchain-member-operator";
-
 #[derive(Subcommand)]
 enum Commands {
     /// Removes kubesaw from a host cluster.
@@ -79,25 +100,43 @@ async fn run() -> color_eyre::Result<()> {
     }
 
     let client = Client::try_default().await?;
+    let config = Config::load(app.config.as_deref())?;
+    let concurrency = app.concurrency;
+    let dry_run = app.dry_run;
+    if dry_run {
+        info!("dry run enabled: no changes will be persisted to the cluster");
+    }
+
+    // Discovery is a single cluster-wide scan; run it once here and share
+    // the snapshot across every CRD instead of letting each one re-scan the
+    // whole apiserver (with `concurrency` CRDs in flight at once, that would
+    // mean that many concurrent full discovery sweeps).
+    let discovery = Discovery::new(client.clone()).run().await?;
 
     if let Some(command) = app.command {
         match command {
-            Commands::Host => run_host(&client).await?,
+            Commands::Host => run_host(&client, &discovery, &config, concurrency, dry_run).await?,
             Commands::Member(args) => {
                 if args.webhook {
-                    remove_webhook_configs(&client)
+                    let removed = remove_webhook_configs(&client, &config.member_label, dry_run)
                         .await
                         .wrap_err("failed to remove stale webhooks, bailing")?;
+                    log_webhook_plan("member", &removed);
                 }
-                run_member(&client).await?;
+                run_member(&client, &discovery, &config, concurrency, dry_run).await?;
             }
             Commands::All(args) => {
                 if args.webhook {
-                    remove_webhook_configs(&client)
+                    let host_removed = remove_webhook_configs(&client, &config.host_label, dry_run)
                         .await
                         .wrap_err("failed to remove stale webhooks, bailing")?;
+                    log_webhook_plan("host", &host_removed);
+                    let member_removed = remove_webhook_configs(&client, &config.member_label, dry_run)
+                        .await
+                        .wrap_err("failed to remove stale webhooks, bailing")?;
+                    log_webhook_plan("member", &member_removed);
                 }
-                run_all(&client).await?
+                run_all(&client, &discovery, &config, concurrency, dry_run).await?
             },
         }
     }
@@ -105,52 +144,121 @@ async fn run() -> color_eyre::Result<()> {
     Ok(())
 }
 
-#[instrument(skip_all)]
-async fn run_member(client: &Client) -> color_eyre::Result<()> {
-    let member_crds = fetch_crds(client, MEMBER_LABEL_KEY).await?;
-    for crd in member_crds {
-        process_crd(client, &crd).await?;
+/// One CRD's worth of planned (or, outside `--dry-run`, performed) work: the
+/// CRD itself and every custom resource whose finalizer was stripped to
+/// allow its removal.
+struct CrdPlan {
+    crd: String,
+    stripped_crs: Vec<String>,
+}
+
+/// Runs [`process_crd`] over `crds` with up to `concurrency` in flight at
+/// once. One CRD failing to process doesn't stop the others; failures are
+/// logged individually and a success/failure summary is reported at the end.
+/// Returns a [`CrdPlan`] for every CRD that was (or would be) removed.
+async fn process_crds(
+    client: &Client,
+    discovery: &Discovery,
+    crds: impl IntoIterator<Item = CustomResourceDefinition>,
+    concurrency: usize,
+    dry_run: bool,
+    finalizers: &[String],
+) -> Vec<CrdPlan> {
+    let results: Vec<_> = stream::iter(crds)
+        .map(|crd| async move {
+            let name = crd.name_any();
+            (
+                name,
+                process_crd(client, discovery, &crd, concurrency, dry_run, finalizers).await,
+            )
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut plan = Vec::new();
+    let mut failed = 0;
+    for (name, result) in results {
+        match result {
+            Ok(stripped_crs) => plan.push(CrdPlan { crd: name, stripped_crs }),
+            Err(err) => {
+                failed += 1;
+                error!(name, ?err, "failed to process crd");
+            }
+        }
     }
+    info!(succeeded = plan.len(), failed, "finished processing crds");
+    plan
+}
 
-    Ok(())
+/// Logs a single aggregated summary of everything a `process_crds` run did
+/// (or, under `--dry-run`, would do) — every CRD and every custom resource
+/// whose finalizer was stripped to allow it — instead of leaving a reader to
+/// reconstruct that from the per-CRD and per-CR log lines above it.
+fn log_crd_plan(scope: &str, plan: &[CrdPlan]) {
+    let crds: Vec<_> = plan.iter().map(|entry| entry.crd.as_str()).collect();
+    let crs_stripped: usize = plan.iter().map(|entry| entry.stripped_crs.len()).sum();
+    for entry in plan {
+        info!(scope, crd = entry.crd, stripped_crs = ?entry.stripped_crs, "crd plan");
+    }
+    info!(scope, crds = ?crds, crs_stripped, "plan summary");
+}
+
+/// Logs an aggregated summary of every webhook-related resource removed (or,
+/// under `--dry-run`, that would be removed) for one label selector.
+fn log_webhook_plan(scope: &str, removed: &[String]) {
+    info!(scope, webhooks_removed = ?removed, count = removed.len(), "webhook plan");
 }
 
 #[instrument(skip_all)]
-async fn run_host(client: &Client) -> color_eyre::Result<()> {
-    let host_crds = fetch_crds(client, HOST_LABEL_KEY).await?;
-    for crd in host_crds {
-        process_crd(client, &crd).await?;
-    }
+async fn run_member(
+    client: &Client,
+    discovery: &Discovery,
+    config: &Config,
+    concurrency: usize,
+    dry_run: bool,
+) -> color_eyre::Result<()> {
+    let member_crds = fetch_crds(client, &config.member_label).await?;
+    let plan = process_crds(client, discovery, member_crds, concurrency, dry_run, &config.finalizers).await;
+    log_crd_plan("member", &plan);
 
     Ok(())
 }
 
 #[instrument(skip_all)]
-async fn run_all(client: &Client) -> color_eyre::Result<()> {
-    let host_crds = fetch_crds(client, HOST_LABEL_KEY).await?;
-    let member_crds = fetch_crds(client, MEMBER_LABEL_KEY).await?;
-
-    for crd in host_crds
-        .iter()
-        .chain(member_crds.iter())
-        .unique_by(|crd| &crd.metadata.name)
-    {
-        process_crd(client, crd).await?;
-    }
+async fn run_host(
+    client: &Client,
+    discovery: &Discovery,
+    config: &Config,
+    concurrency: usize,
+    dry_run: bool,
+) -> color_eyre::Result<()> {
+    let host_crds = fetch_crds(client, &config.host_label).await?;
+    let plan = process_crds(client, discovery, host_crds, concurrency, dry_run, &config.finalizers).await;
+    log_crd_plan("host", &plan);
 
     Ok(())
 }
 
-async fn remove_webhook_configs(client: &Client) -> color_eyre::Result<()> {
-    let webhooks: Api<ValidatingWebhookConfiguration> = Api::all(client.clone());
-    webhooks
-        .delete(
-            "member-operator-validating-webhook-toolchain-member-operator",
-            &Default::default(),
-        )
-        .await?
-        .map_left(|_| info!("deleting webhooks"))
-        .map_right(|_| info!("deleted webhook config"));
+#[instrument(skip_all)]
+async fn run_all(
+    client: &Client,
+    discovery: &Discovery,
+    config: &Config,
+    concurrency: usize,
+    dry_run: bool,
+) -> color_eyre::Result<()> {
+    let host_crds = fetch_crds(client, &config.host_label).await?;
+    let member_crds = fetch_crds(client, &config.member_label).await?;
+
+    let crds: Vec<_> = host_crds
+        .into_iter()
+        .chain(member_crds)
+        .unique_by(|crd| crd.metadata.name.clone())
+        .collect();
+
+    let plan = process_crds(client, discovery, crds, concurrency, dry_run, &config.finalizers).await;
+    log_crd_plan("all", &plan);
 
     Ok(())
 }
@@ -0,0 +1,77 @@
+use kube::{
+    api::{DeleteParams, ListParams},
+    core::{Expression, Resource},
+    Api, Client, ResourceExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{info, instrument};
+
+/// Deletes every admission-webhook resource carrying `label`: the
+/// [`ValidatingWebhookConfiguration`]s and [`MutatingWebhookConfiguration`]s
+/// themselves, plus every `Service` and `Secret` carrying it. Services and
+/// Secrets are selected by the label alone; they are not checked to back a
+/// webhook.
+///
+/// Resources are discovered by label rather than by name so that this keeps
+/// working across kubesaw releases that rename the webhook, its Service, or
+/// its TLS Secret. Returns a `kind/name` identifier for every resource
+/// removed (or, under `--dry-run`, that would have been), for the caller to
+/// fold into a plan summary.
+///
+/// [`ValidatingWebhookConfiguration`]: k8s_openapi::api::admissionregistration::v1::ValidatingWebhookConfiguration
+/// [`MutatingWebhookConfiguration`]: k8s_openapi::api::admissionregistration::v1::MutatingWebhookConfiguration
+#[instrument(skip_all, fields(label))]
+pub async fn remove_webhook_configs(
+    client: &Client,
+    label: &str,
+    dry_run: bool,
+) -> color_eyre::Result<Vec<String>> {
+    use k8s_openapi::api::{
+        admissionregistration::v1::{MutatingWebhookConfiguration, ValidatingWebhookConfiguration},
+        core::v1::{Secret, Service},
+    };
+
+    let mut removed = Vec::new();
+    removed.extend(delete_labeled::<ValidatingWebhookConfiguration>(client, label, dry_run).await?);
+    removed.extend(delete_labeled::<MutatingWebhookConfiguration>(client, label, dry_run).await?);
+    removed.extend(delete_labeled::<Service>(client, label, dry_run).await?);
+    removed.extend(delete_labeled::<Secret>(client, label, dry_run).await?);
+
+    Ok(removed)
+}
+
+/// Lists every `K` carrying `label` and deletes them, namespaced or not,
+/// returning a `kind/name` identifier for each one removed.
+async fn delete_labeled<K>(
+    client: &Client,
+    label: &str,
+    dry_run: bool,
+) -> color_eyre::Result<Vec<String>>
+where
+    K: Resource<DynamicType = ()> + Clone + std::fmt::Debug + DeserializeOwned + Serialize,
+{
+    let api: Api<K> = Api::all(client.clone());
+    let expr = Expression::Exists(label.to_owned());
+    let lp = ListParams::default().labels_from(&expr.into());
+    let dp = DeleteParams {
+        dry_run,
+        ..Default::default()
+    };
+
+    let mut removed = Vec::new();
+    for item in api.list(&lp).await? {
+        let name = item.name_any();
+        let api: Api<K> = match item.namespace() {
+            Some(namespace) => Api::namespaced(client.clone(), &namespace),
+            None => Api::all(client.clone()),
+        };
+
+        api.delete(&name, &dp)
+            .await?
+            .map_left(|_| info!(name, dry_run, kind = K::kind(&()).as_ref(), "deleting webhook resource"))
+            .map_right(|_| info!(name, dry_run, kind = K::kind(&()).as_ref(), "deleted webhook resource"));
+        removed.push(format!("{}/{name}", K::kind(&())));
+    }
+
+    Ok(removed)
+}